@@ -1,18 +1,35 @@
 //! Trying out custom messages.
 //! Using some messages found in [BOLT #13]: https://github.com/sr-gi/bolt13/blob/master/13-watchtowers.md
 
-use bitcoin::secp256k1::key::PublicKey;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes128Gcm, Key, Nonce};
+use bitcoin::hashes::sha256d;
+use bitcoin::hashes::Hash as _;
+use bitcoin::secp256k1;
+use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+use bitcoin::secp256k1::{Message, Secp256k1, Signature};
+use bitcoin::{Block, BlockHeader, Transaction};
 use core::mem;
+use lightning::chain::chaininterface::BroadcasterInterface;
+use lightning::chain::Listen;
 use lightning::ln::channelmanager::SimpleArcChannelManager;
 use lightning::ln::msgs::{DecodeError, ErrorAction, LightningError, WarningMessage};
 use lightning::ln::peer_handler::{CustomMessageHandler, PeerManager};
 use lightning::ln::wire::{CustomMessageReader, Type};
 use lightning::routing::network_graph::{NetGraphMsgHandler, NetworkGraph};
 use lightning::util::logger;
-use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning::util::ser::{BigSize, Readable, Writeable, Writer};
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::io;
 use std::sync::{Arc, Mutex};
 
+/// The locator identifying an appointment: the first half of the breach
+/// transaction's txid, as per [BOLT #13].
+///
+/// [BOLT #13]: https://github.com/sr-gi/bolt13/blob/master/13-watchtowers.md
+pub type Locator = [u8; 16];
+
 /// The register message: The user would send this message to the tower to
 /// register for the watching service.
 #[derive(Debug)]
@@ -31,6 +48,51 @@ pub struct SubscriptionDetails {
 	pub amount_msat: u32,
 }
 
+/// Sent by a registered user to ask the tower to watch for the breach
+/// transaction identified by `locator`, carrying the encrypted penalty
+/// transaction (the "blob") that the tower should broadcast if that breach
+/// is ever seen on-chain.
+#[derive(Debug)]
+pub struct AddUpdateAppointment {
+	pub locator: Locator,
+	pub encrypted_blob: Vec<u8>,
+	pub to_self_delay: u16,
+}
+
+/// The tower's positive response to an [`AddUpdateAppointment`], echoing the
+/// locator and the block height the tower started watching from, signed over
+/// with the tower's node key so the user can prove the tower accepted it.
+#[derive(Debug)]
+pub struct AppointmentAccepted {
+	pub locator: Locator,
+	pub start_block: u32,
+	pub signature: Signature,
+}
+
+/// The tower's negative response to an [`AddUpdateAppointment`], giving a
+/// machine-readable `rcode` and a human-readable `reason` the appointment
+/// couldn't be taken (e.g. the subscription is exhausted or expired).
+#[derive(Debug)]
+pub struct AppointmentRejected {
+	pub locator: Locator,
+	pub rcode: u16,
+	pub reason: String,
+}
+
+/// Sent by a user to fetch back the data of a previously accepted appointment.
+#[derive(Debug)]
+pub struct GetAppointment {
+	pub locator: Locator,
+}
+
+/// The tower's response to a [`GetAppointment`], returning the encrypted
+/// penalty transaction blob stored for that locator.
+#[derive(Debug)]
+pub struct AppointmentData {
+	pub locator: Locator,
+	pub encrypted_blob: Vec<u8>,
+}
+
 /// Defines a constant type identifier for reading messages from the wire.
 /// Just like the private [`lightning::ln::wire::Encode`].
 pub trait Encode {
@@ -38,6 +100,85 @@ pub trait Encode {
 	const TYPE: u16;
 }
 
+/// A [`Writer`] that just accumulates bytes in memory, used to measure a
+/// single TLV record's value before writing its length prefix.
+struct TlvValueWriter(Vec<u8>);
+
+impl io::Write for TlvValueWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.extend_from_slice(buf);
+		Ok(buf.len())
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Writer for TlvValueWriter {
+	fn size_hint(&mut self, size: usize) {
+		self.0.reserve(size);
+	}
+}
+
+/// Writes a single field as a BOLT #1 TLV record: a BigSize `type`, a
+/// BigSize `length`, and the field's serialized bytes.
+fn write_tlv_field<W: Writer, T: Writeable>(
+	writer: &mut W, field_type: u64, value: &T,
+) -> Result<(), io::Error> {
+	let mut buf = TlvValueWriter(Vec::new());
+	value.write(&mut buf)?;
+	BigSize(field_type).write(writer)?;
+	BigSize(buf.0.len() as u64).write(writer)?;
+	writer.write_all(&buf.0)
+}
+
+/// The largest value a single TLV record can declare. A record can never
+/// legitimately be larger than an entire Lightning message (BOLT #1), so
+/// this also caps the allocation we're willing to make for it below.
+const MAX_TLV_RECORD_LEN: u64 = 65535;
+
+/// Reads a full BOLT #1 TLV stream until EOF, returning each record's `type`
+/// and raw value bytes in the order they appear on the wire. Record types
+/// must appear in strictly ascending order, per BOLT #1.
+fn read_tlv_stream<R: io::Read>(reader: &mut R) -> Result<Vec<(u64, Vec<u8>)>, DecodeError> {
+	let mut records = Vec::new();
+	let mut last_type: Option<u64> = None;
+	loop {
+		let field_type = match BigSize::read(reader) {
+			Ok(BigSize(field_type)) => field_type,
+			// We've read every record; this is the only valid place to run out of bytes.
+			Err(DecodeError::ShortRead) => break,
+			Err(e) => return Err(e),
+		};
+		if last_type.map_or(false, |last_type| field_type <= last_type) {
+			return Err(DecodeError::InvalidValue);
+		}
+		last_type = Some(field_type);
+		let BigSize(length) = BigSize::read(reader)?;
+		// Don't take the peer's word for `length` before we've got the bytes to back it
+		// up: a record claiming a multi-gigabyte length would otherwise make us allocate
+		// that much memory before `read_exact` ever gets a chance to fail.
+		if length > MAX_TLV_RECORD_LEN {
+			return Err(DecodeError::InvalidValue);
+		}
+		let mut value = vec![0u8; length as usize];
+		reader.read_exact(&mut value).map_err(|_| DecodeError::ShortRead)?;
+		records.push((field_type, value));
+	}
+	Ok(records)
+}
+
+/// Parses a TLV record's raw value bytes into a field, erroring if any bytes
+/// are left over once the field has been read.
+fn parse_tlv_value<T: Readable>(value: &[u8]) -> Result<T, DecodeError> {
+	let mut cursor = value;
+	let parsed = T::read(&mut cursor)?;
+	if !cursor.is_empty() {
+		return Err(DecodeError::InvalidValue);
+	}
+	Ok(parsed)
+}
+
 impl Encode for Register {
 	// An arbitrary even type.
 	const TYPE: u16 = 45768;
@@ -46,10 +187,24 @@ impl Encode for Register {
 /// Make the register message readable.
 impl Readable for Register {
 	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut pubkey = None;
+		let mut appointment_slots = None;
+		let mut subscription_period = None;
+		for (field_type, value) in read_tlv_stream(reader)? {
+			match field_type {
+				0 => pubkey = Some(parse_tlv_value(&value)?),
+				2 => appointment_slots = Some(parse_tlv_value(&value)?),
+				4 => subscription_period = Some(parse_tlv_value(&value)?),
+				// An unknown even type is mandatory and we don't understand it.
+				t if t % 2 == 0 => return Err(DecodeError::InvalidValue),
+				// An unknown odd type is optional; we've already consumed its bytes above.
+				_ => {}
+			}
+		}
 		Ok(Self {
-			pubkey: Readable::read(reader)?,
-			appointment_slots: Readable::read(reader)?,
-			subscription_period: Readable::read(reader)?,
+			pubkey: pubkey.ok_or(DecodeError::InvalidValue)?,
+			appointment_slots: appointment_slots.ok_or(DecodeError::InvalidValue)?,
+			subscription_period: subscription_period.ok_or(DecodeError::InvalidValue)?,
 		})
 	}
 }
@@ -57,9 +212,9 @@ impl Readable for Register {
 /// The tower won't actually need this implementation, only the registerer will.
 impl Writeable for Register {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
-		self.pubkey.write(writer)?;
-		self.appointment_slots.write(writer)?;
-		self.subscription_period.write(writer)?;
+		write_tlv_field(writer, 0, &self.pubkey)?;
+		write_tlv_field(writer, 2, &self.appointment_slots)?;
+		write_tlv_field(writer, 4, &self.subscription_period)?;
 		Ok(())
 	}
 }
@@ -72,9 +227,19 @@ impl Encode for SubscriptionDetails {
 /// The tower won't actually need this implementation, only the registerer will.
 impl Readable for SubscriptionDetails {
 	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut appointment_max_size = None;
+		let mut amount_msat = None;
+		for (field_type, value) in read_tlv_stream(reader)? {
+			match field_type {
+				0 => appointment_max_size = Some(parse_tlv_value(&value)?),
+				2 => amount_msat = Some(parse_tlv_value(&value)?),
+				t if t % 2 == 0 => return Err(DecodeError::InvalidValue),
+				_ => {}
+			}
+		}
 		Ok(Self {
-			appointment_max_size: Readable::read(reader)?,
-			amount_msat: Readable::read(reader)?,
+			appointment_max_size: appointment_max_size.ok_or(DecodeError::InvalidValue)?,
+			amount_msat: amount_msat.ok_or(DecodeError::InvalidValue)?,
 		})
 	}
 }
@@ -82,8 +247,184 @@ impl Readable for SubscriptionDetails {
 /// Make the subscription details message writable.
 impl Writeable for SubscriptionDetails {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
-		self.appointment_max_size.write(writer)?;
-		self.amount_msat.write(writer)?;
+		write_tlv_field(writer, 0, &self.appointment_max_size)?;
+		write_tlv_field(writer, 2, &self.amount_msat)?;
+		Ok(())
+	}
+}
+
+impl Encode for AddUpdateAppointment {
+	// An arbitrary even type.
+	const TYPE: u16 = 45772;
+}
+
+/// Make the add/update appointment message readable.
+impl Readable for AddUpdateAppointment {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut locator = None;
+		let mut encrypted_blob = None;
+		let mut to_self_delay = None;
+		for (field_type, value) in read_tlv_stream(reader)? {
+			match field_type {
+				0 => locator = Some(parse_tlv_value(&value)?),
+				2 => encrypted_blob = Some(parse_tlv_value(&value)?),
+				4 => to_self_delay = Some(parse_tlv_value(&value)?),
+				t if t % 2 == 0 => return Err(DecodeError::InvalidValue),
+				_ => {}
+			}
+		}
+		Ok(Self {
+			locator: locator.ok_or(DecodeError::InvalidValue)?,
+			encrypted_blob: encrypted_blob.ok_or(DecodeError::InvalidValue)?,
+			to_self_delay: to_self_delay.ok_or(DecodeError::InvalidValue)?,
+		})
+	}
+}
+
+/// Make the add/update appointment message writable.
+impl Writeable for AddUpdateAppointment {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_tlv_field(writer, 0, &self.locator)?;
+		write_tlv_field(writer, 2, &self.encrypted_blob)?;
+		write_tlv_field(writer, 4, &self.to_self_delay)?;
+		Ok(())
+	}
+}
+
+impl Encode for AppointmentAccepted {
+	// An arbitrary even type.
+	const TYPE: u16 = 45774;
+}
+
+/// Make the appointment accepted message readable.
+impl Readable for AppointmentAccepted {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut locator = None;
+		let mut start_block = None;
+		let mut signature = None;
+		for (field_type, value) in read_tlv_stream(reader)? {
+			match field_type {
+				0 => locator = Some(parse_tlv_value(&value)?),
+				2 => start_block = Some(parse_tlv_value(&value)?),
+				4 => signature = Some(parse_tlv_value(&value)?),
+				t if t % 2 == 0 => return Err(DecodeError::InvalidValue),
+				_ => {}
+			}
+		}
+		Ok(Self {
+			locator: locator.ok_or(DecodeError::InvalidValue)?,
+			start_block: start_block.ok_or(DecodeError::InvalidValue)?,
+			signature: signature.ok_or(DecodeError::InvalidValue)?,
+		})
+	}
+}
+
+/// Make the appointment accepted message writable.
+impl Writeable for AppointmentAccepted {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_tlv_field(writer, 0, &self.locator)?;
+		write_tlv_field(writer, 2, &self.start_block)?;
+		write_tlv_field(writer, 4, &self.signature)?;
+		Ok(())
+	}
+}
+
+impl Encode for AppointmentRejected {
+	// An arbitrary even type.
+	const TYPE: u16 = 45776;
+}
+
+/// Make the appointment rejected message readable.
+impl Readable for AppointmentRejected {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut locator = None;
+		let mut rcode = None;
+		let mut reason = None;
+		for (field_type, value) in read_tlv_stream(reader)? {
+			match field_type {
+				0 => locator = Some(parse_tlv_value(&value)?),
+				2 => rcode = Some(parse_tlv_value(&value)?),
+				4 => reason = Some(parse_tlv_value(&value)?),
+				t if t % 2 == 0 => return Err(DecodeError::InvalidValue),
+				_ => {}
+			}
+		}
+		Ok(Self {
+			locator: locator.ok_or(DecodeError::InvalidValue)?,
+			rcode: rcode.ok_or(DecodeError::InvalidValue)?,
+			reason: reason.ok_or(DecodeError::InvalidValue)?,
+		})
+	}
+}
+
+/// Make the appointment rejected message writable.
+impl Writeable for AppointmentRejected {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_tlv_field(writer, 0, &self.locator)?;
+		write_tlv_field(writer, 2, &self.rcode)?;
+		write_tlv_field(writer, 4, &self.reason)?;
+		Ok(())
+	}
+}
+
+impl Encode for GetAppointment {
+	// An arbitrary even type.
+	const TYPE: u16 = 45778;
+}
+
+/// Make the get appointment message readable.
+impl Readable for GetAppointment {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut locator = None;
+		for (field_type, value) in read_tlv_stream(reader)? {
+			match field_type {
+				0 => locator = Some(parse_tlv_value(&value)?),
+				t if t % 2 == 0 => return Err(DecodeError::InvalidValue),
+				_ => {}
+			}
+		}
+		Ok(Self { locator: locator.ok_or(DecodeError::InvalidValue)? })
+	}
+}
+
+/// Make the get appointment message writable.
+impl Writeable for GetAppointment {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_tlv_field(writer, 0, &self.locator)?;
+		Ok(())
+	}
+}
+
+impl Encode for AppointmentData {
+	// An arbitrary even type.
+	const TYPE: u16 = 45780;
+}
+
+/// Make the appointment data message readable.
+impl Readable for AppointmentData {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut locator = None;
+		let mut encrypted_blob = None;
+		for (field_type, value) in read_tlv_stream(reader)? {
+			match field_type {
+				0 => locator = Some(parse_tlv_value(&value)?),
+				2 => encrypted_blob = Some(parse_tlv_value(&value)?),
+				t if t % 2 == 0 => return Err(DecodeError::InvalidValue),
+				_ => {}
+			}
+		}
+		Ok(Self {
+			locator: locator.ok_or(DecodeError::InvalidValue)?,
+			encrypted_blob: encrypted_blob.ok_or(DecodeError::InvalidValue)?,
+		})
+	}
+}
+
+/// Make the appointment data message writable.
+impl Writeable for AppointmentData {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_tlv_field(writer, 0, &self.locator)?;
+		write_tlv_field(writer, 2, &self.encrypted_blob)?;
 		Ok(())
 	}
 }
@@ -93,7 +434,11 @@ impl Writeable for SubscriptionDetails {
 pub enum TowerMessage {
 	Register(Register),
 	SubscriptionDetails(SubscriptionDetails),
-	// Other msgs go here ...
+	AddUpdateAppointment(AddUpdateAppointment),
+	AppointmentAccepted(AppointmentAccepted),
+	AppointmentRejected(AppointmentRejected),
+	GetAppointment(GetAppointment),
+	AppointmentData(AppointmentData),
 }
 
 impl Type for TowerMessage {
@@ -101,6 +446,11 @@ impl Type for TowerMessage {
 		match self {
 			TowerMessage::Register(..) => Register::TYPE,
 			TowerMessage::SubscriptionDetails(..) => SubscriptionDetails::TYPE,
+			TowerMessage::AddUpdateAppointment(..) => AddUpdateAppointment::TYPE,
+			TowerMessage::AppointmentAccepted(..) => AppointmentAccepted::TYPE,
+			TowerMessage::AppointmentRejected(..) => AppointmentRejected::TYPE,
+			TowerMessage::GetAppointment(..) => GetAppointment::TYPE,
+			TowerMessage::AppointmentData(..) => AppointmentData::TYPE,
 		}
 	}
 }
@@ -114,30 +464,158 @@ impl Writeable for TowerMessage {
 			TowerMessage::SubscriptionDetails(msg) => {
 				msg.write(writer)?;
 			}
+			// A tower won't normally send these messages to anybody either, only
+			// a registered user would.
+			TowerMessage::AddUpdateAppointment(msg) => msg.write(writer)?,
+			TowerMessage::GetAppointment(msg) => msg.write(writer)?,
+			TowerMessage::AppointmentAccepted(msg) => {
+				msg.write(writer)?;
+			}
+			TowerMessage::AppointmentRejected(msg) => {
+				msg.write(writer)?;
+			}
+			TowerMessage::AppointmentData(msg) => {
+				msg.write(writer)?;
+			}
 		}
 		Ok(())
 	}
 }
 
+/// Builds a [`LightningError`] that answers the peer with a warning message
+/// (without disconnecting it), used whenever a message arrives from a node
+/// id that isn't allowed to send it.
+fn unexpected_message_error(description: &str) -> LightningError {
+	LightningError {
+		err: format!("{} wasn't expected!", description),
+		action: ErrorAction::SendWarningMessage {
+			msg: WarningMessage { channel_id: [0; 32], data: format!("You sent me a {}!", description) },
+			log_level: logger::Level::Debug,
+		},
+	}
+}
+
+/// Decrypts a stored appointment's penalty-transaction blob using the breach
+/// txid's second half as the (single-use) decryption key, per [BOLT #13]'s
+/// AES-128-GCM encryption scheme. The nonce is fixed at zero, which is safe
+/// here because each key is only ever used to encrypt a single blob.
+///
+/// [BOLT #13]: https://github.com/sr-gi/bolt13/blob/master/13-watchtowers.md
+fn decrypt_penalty_transaction(encrypted_blob: &[u8], key: &[u8; 16]) -> Result<Transaction, ()> {
+	let cipher = Aes128Gcm::new(Key::from_slice(key));
+	let plaintext = cipher.decrypt(Nonce::from_slice(&[0u8; 12]), encrypted_blob).map_err(|_| ())?;
+	bitcoin::consensus::encode::deserialize(&plaintext).map_err(|_| ())
+}
+
+/// A subscriber's watching-service subscription: how many appointment slots
+/// it has left and the block height it expires at.
+#[derive(Debug, Clone, Copy)]
+pub struct Subscription {
+	pub available_slots: u32,
+	pub expiry_block: u32,
+}
+
 /// A handler to handle the incoming [`TowerMessage`]s.
+///
+/// Breach detection is done by scanning every confirmed transaction's txid
+/// prefix against the stored locators in [`Listen::block_connected`] — we
+/// only ever learn a locator, never a full txid, so there's nothing precise
+/// to hand a pruned chain source through [`chain::Filter`]. This tower needs
+/// full blocks.
+///
+/// [`chain::Filter`]: lightning::chain::Filter
 pub struct TowerMessageHandler {
 	msg_q: Mutex<Vec<(PublicKey, TowerMessage)>>,
+	/// Each subscriber's negotiated subscription, keyed by their node id. A
+	/// subscriber with no entry here has never registered.
+	subscriptions: Mutex<HashMap<PublicKey, Subscription>>,
+	/// Accepted appointments, keyed by their locator, alongside the node id
+	/// that paid for them.
+	appointments: Mutex<HashMap<Locator, (Vec<u8>, PublicKey)>>,
+	/// The tower's own key, used to sign appointment receipts.
+	node_secret: SecretKey,
+	secp_ctx: Secp256k1<secp256k1::All>,
+	/// Where resolved justice transactions get broadcast.
+	broadcaster: Arc<dyn BroadcasterInterface + Send + Sync>,
+	/// The chain tip height last seen through [`Listen::block_connected`],
+	/// used to compute a fresh subscription's `expiry_block` and an
+	/// appointment's `start_block`.
+	tip_height: Mutex<u32>,
 }
 
 impl TowerMessageHandler {
-	pub fn new() -> Self {
-		Self { msg_q: Mutex::new(Vec::new()) }
+	pub fn new(node_secret: SecretKey, broadcaster: Arc<dyn BroadcasterInterface + Send + Sync>) -> Self {
+		Self {
+			msg_q: Mutex::new(Vec::new()),
+			subscriptions: Mutex::new(HashMap::new()),
+			appointments: Mutex::new(HashMap::new()),
+			node_secret,
+			secp_ctx: Secp256k1::new(),
+			broadcaster,
+			tip_height: Mutex::new(0),
+		}
+	}
+
+	/// Signs over an appointment's `locator` and `start_block`, producing the
+	/// receipt signature returned in an [`AppointmentAccepted`] message.
+	fn sign_receipt(&self, locator: &Locator, start_block: u32) -> Signature {
+		let mut preimage = Vec::with_capacity(locator.len() + 4);
+		preimage.extend_from_slice(locator);
+		preimage.extend_from_slice(&start_block.to_be_bytes());
+		let digest = sha256d::Hash::hash(&preimage);
+		let msg = Message::from_slice(&digest[..]).unwrap();
+		self.secp_ctx.sign(&msg, &self.node_secret)
+	}
+
+	/// Checks a confirmed transaction's txid against every stored
+	/// appointment's locator; on a match, decrypts and broadcasts the justice
+	/// transaction and restores the payer's appointment slot.
+	fn try_resolve_breach(&self, tx: &Transaction) {
+		let txid_bytes = *tx.txid().as_inner();
+		let locator: Locator = txid_bytes[..16].try_into().unwrap();
+		let (encrypted_blob, payer) = match self.appointments.lock().unwrap().remove(&locator) {
+			Some(entry) => entry,
+			None => return,
+		};
+		let key: [u8; 16] = txid_bytes[16..].try_into().unwrap();
+		match decrypt_penalty_transaction(&encrypted_blob, &key) {
+			Ok(penalty_tx) => self.broadcaster.broadcast_transaction(&penalty_tx),
+			Err(()) => {
+				println!("Failed to decrypt the penalty transaction for locator {:?}.", locator)
+			}
+		}
+		if let Some(subscription) = self.subscriptions.lock().unwrap().get_mut(&payer) {
+			subscription.available_slots += 1;
+		}
 	}
 
-	pub fn handle_tower_message(msg: TowerMessage) -> Result<TowerMessage, LightningError> {
+	pub fn handle_tower_message(
+		&self, sender_node_id: &PublicKey, msg: TowerMessage,
+	) -> Result<TowerMessage, LightningError> {
 		match msg {
 			TowerMessage::Register(msg) => {
 				println!(
 					"Received a Register message: {:?}.\nResponding with a SubscriptionDetails message.", msg
 				);
+				let current_height = *self.tip_height.lock().unwrap();
+				let mut subscriptions = self.subscriptions.lock().unwrap();
+				// A re-registration extends the existing subscription rather than
+				// replacing it, so a subscriber can top up slots before they run out.
+				let subscription = subscriptions.entry(sender_node_id.clone()).or_insert(
+					Subscription { available_slots: 0, expiry_block: current_height },
+				);
+				// `appointment_slots` and `subscription_period` come straight off the
+				// wire, so saturate instead of risking an overflow panic on a
+				// maliciously (or just repeatedly) large registration.
+				subscription.available_slots = subscription.available_slots.saturating_add(msg.appointment_slots);
+				// Extend from whichever is later: the subscription's current expiry, or
+				// now. Otherwise a top-up with a shorter `subscription_period` than the
+				// subscriber already had left would shorten a still-valid subscription.
+				subscription.expiry_block =
+					subscription.expiry_block.max(current_height).saturating_add(msg.subscription_period);
 				let appointment_max_size = 30;
-				// Pay for the Storage * Time.
-				let amount_msat = msg.appointment_slots * msg.subscription_period;
+				// Pay for the Storage * Time, based on the subscription as it now stands.
+				let amount_msat = subscription.available_slots.saturating_mul(msg.subscription_period);
 				Ok(TowerMessage::SubscriptionDetails(SubscriptionDetails {
 					appointment_max_size,
 					amount_msat,
@@ -146,18 +624,102 @@ impl TowerMessageHandler {
 			TowerMessage::SubscriptionDetails(msg) => {
 				println!("Received a SubscriptionDetails message: {:?}.\nIgnoring it.", msg);
 				// A tower shouldn't normally receive this message.
-				Err(LightningError {
-					err: "A SubscriptionDetails message wasn't expected!".to_string(),
-					action: ErrorAction::SendWarningMessage {
-						msg: WarningMessage {
-							channel_id: [0; 32],
-							data:
-								"You sent me a SubscriptionDetails message but I didn't register!"
-									.to_string(),
-						},
-						log_level: logger::Level::Debug,
-					},
-				})
+				Err(unexpected_message_error("SubscriptionDetails message but I didn't register"))
+			}
+			TowerMessage::AddUpdateAppointment(msg) => {
+				println!("Received an AddUpdateAppointment message: {:?}.", msg);
+				let current_height = *self.tip_height.lock().unwrap();
+				// A locator already held by a *different* subscriber can't be taken
+				// over; only the subscriber that owns it may update it, in which
+				// case no fresh slot is spent.
+				let existing_payer =
+					self.appointments.lock().unwrap().get(&msg.locator).map(|(_, payer)| payer.clone());
+				if let Some(existing_payer) = &existing_payer {
+					if existing_payer != sender_node_id {
+						return Ok(TowerMessage::AppointmentRejected(AppointmentRejected {
+							locator: msg.locator,
+							rcode: 4,
+							reason: "locator already in use by another subscriber".to_string(),
+						}));
+					}
+				}
+				let is_update = existing_payer.is_some();
+				match self.subscriptions.lock().unwrap().get_mut(sender_node_id) {
+					Some(subscription) if subscription.expiry_block < current_height => {
+						return Ok(TowerMessage::AppointmentRejected(AppointmentRejected {
+							locator: msg.locator,
+							rcode: 3,
+							reason: "your subscription has expired".to_string(),
+						}));
+					}
+					Some(subscription) if is_update || subscription.available_slots > 0 => {
+						// Updating an appointment you already hold doesn't cost a slot;
+						// only a genuinely new appointment does.
+						if !is_update {
+							subscription.available_slots -= 1;
+						}
+					}
+					Some(_) => {
+						return Ok(TowerMessage::AppointmentRejected(AppointmentRejected {
+							locator: msg.locator,
+							rcode: 2,
+							reason: "no appointment slots left on your subscription".to_string(),
+						}));
+					}
+					None => {
+						return Err(unexpected_message_error(
+							"AddUpdateAppointment message but you never registered",
+						));
+					}
+				}
+				let start_block = current_height;
+				self.appointments
+					.lock()
+					.unwrap()
+					.insert(msg.locator, (msg.encrypted_blob, sender_node_id.clone()));
+				let signature = self.sign_receipt(&msg.locator, start_block);
+				Ok(TowerMessage::AppointmentAccepted(AppointmentAccepted {
+					locator: msg.locator,
+					start_block,
+					signature,
+				}))
+			}
+			TowerMessage::GetAppointment(msg) => {
+				println!("Received a GetAppointment message: {:?}.", msg);
+				if !self.subscriptions.lock().unwrap().contains_key(sender_node_id) {
+					return Err(unexpected_message_error(
+						"GetAppointment message but you never registered",
+					));
+				}
+				match self.appointments.lock().unwrap().get(&msg.locator) {
+					// Only the subscriber that paid for the appointment may read it back.
+					Some((encrypted_blob, payer)) if payer == sender_node_id => {
+						Ok(TowerMessage::AppointmentData(AppointmentData {
+							locator: msg.locator,
+							encrypted_blob: encrypted_blob.clone(),
+						}))
+					}
+					_ => Ok(TowerMessage::AppointmentRejected(AppointmentRejected {
+						locator: msg.locator,
+						rcode: 1,
+						reason: "no appointment found for this locator".to_string(),
+					})),
+				}
+			}
+			TowerMessage::AppointmentAccepted(msg) => {
+				println!("Received an AppointmentAccepted message: {:?}.\nIgnoring it.", msg);
+				// A tower shouldn't normally receive this message.
+				Err(unexpected_message_error("AppointmentAccepted message but I'm not a user"))
+			}
+			TowerMessage::AppointmentRejected(msg) => {
+				println!("Received an AppointmentRejected message: {:?}.\nIgnoring it.", msg);
+				// A tower shouldn't normally receive this message.
+				Err(unexpected_message_error("AppointmentRejected message but I'm not a user"))
+			}
+			TowerMessage::AppointmentData(msg) => {
+				println!("Received an AppointmentData message: {:?}.\nIgnoring it.", msg);
+				// A tower shouldn't normally receive this message.
+				Err(unexpected_message_error("AppointmentData message but I'm not a user"))
 			}
 		}
 	}
@@ -180,6 +742,23 @@ impl CustomMessageReader for TowerMessageHandler {
 			SubscriptionDetails::TYPE => {
 				Ok(Some(TowerMessage::SubscriptionDetails(Readable::read(buffer)?)))
 			}
+			AddUpdateAppointment::TYPE => {
+				Ok(Some(TowerMessage::AddUpdateAppointment(Readable::read(buffer)?)))
+			}
+			GetAppointment::TYPE => {
+				Ok(Some(TowerMessage::GetAppointment(Readable::read(buffer)?)))
+			}
+			// Similarly, the tower is the only one that ever sends these, so we
+			// won't ever need to read them either.
+			AppointmentAccepted::TYPE => {
+				Ok(Some(TowerMessage::AppointmentAccepted(Readable::read(buffer)?)))
+			}
+			AppointmentRejected::TYPE => {
+				Ok(Some(TowerMessage::AppointmentRejected(Readable::read(buffer)?)))
+			}
+			AppointmentData::TYPE => {
+				Ok(Some(TowerMessage::AppointmentData(Readable::read(buffer)?)))
+			}
 			// Unknown message.
 			_ => Ok(None),
 		}
@@ -194,7 +773,7 @@ impl CustomMessageHandler for TowerMessageHandler {
 			.msg_q
 			.lock()
 			.unwrap()
-			.push((sender_node_id.clone(), Self::handle_tower_message(msg)?)))
+			.push((sender_node_id.clone(), self.handle_tower_message(sender_node_id, msg)?)))
 	}
 
 	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, TowerMessage)> {
@@ -202,15 +781,505 @@ impl CustomMessageHandler for TowerMessageHandler {
 	}
 }
 
+impl Listen for TowerMessageHandler {
+	fn block_connected(&self, block: &Block, height: u32) {
+		*self.tip_height.lock().unwrap() = height;
+		for tx in &block.txdata {
+			self.try_resolve_breach(tx);
+		}
+	}
+
+	fn block_disconnected(&self, _header: &BlockHeader, _height: u32) {
+		// `try_resolve_breach` already removed the appointment the moment its
+		// breach transaction was first seen, and `Listen::block_disconnected`
+		// doesn't hand us the disconnected block's transactions, so we can't
+		// tell whether this disconnect un-confirmed that breach. If it did,
+		// the appointment is gone for good and won't be re-detected even if
+		// the same breach later confirms again on the winning chain. This is
+		// an accepted limitation of this demo, not real watchtower behavior.
+	}
+}
+
+/// Combines several custom-message handlers, each owning a disjoint set of
+/// [`Type::type_id`]s, into a single handler that can be dropped into a
+/// [`PeerManager`]'s custom-message slot.
+///
+/// Generates:
+/// - a combined message enum, one variant per inner handler, implementing
+///   [`Type`] and [`Writeable`] by delegating to whichever variant is active;
+/// - a combined handler struct holding an `Arc` of each inner handler, whose
+///   [`CustomMessageReader::read`] tries each inner handler in turn (the
+///   first to recognize the `message_type` wins) and whose
+///   [`CustomMessageHandler::handle_custom_message`] routes the message back
+///   to the handler that produced its variant;
+/// - a [`CustomMessageHandler::get_and_clear_pending_msg`] that concatenates
+///   every inner handler's pending queue.
+///
+/// This avoids duplicating type-id constants across unrelated
+/// application-specific protocols (the tower, DLCs, ...) that all want to
+/// share a node's single custom-message slot.
+macro_rules! composite_custom_message_handler {
+	(
+		$handler_name:ident, $msg_enum:ident, {
+			$( $field:ident : $handler_ty:ty => $msg_ty:ty as $variant:ident ),+ $(,)?
+		}
+	) => {
+		#[derive(Debug)]
+		pub enum $msg_enum {
+			$( $variant($msg_ty) ),+
+		}
+
+		impl Type for $msg_enum {
+			fn type_id(&self) -> u16 {
+				match self {
+					$( $msg_enum::$variant(msg) => Type::type_id(msg), )+
+				}
+			}
+		}
+
+		impl Writeable for $msg_enum {
+			fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+				match self {
+					$( $msg_enum::$variant(msg) => Writeable::write(msg, writer), )+
+				}
+			}
+		}
+
+		pub struct $handler_name {
+			$( pub $field: Arc<$handler_ty>, )+
+		}
+
+		impl $handler_name {
+			pub fn new($( $field: Arc<$handler_ty> ),+) -> Self {
+				Self { $( $field ),+ }
+			}
+		}
+
+		impl CustomMessageReader for $handler_name {
+			type CustomMessage = $msg_enum;
+
+			fn read<R: io::Read>(
+				&self, message_type: u16, buffer: &mut R,
+			) -> Result<Option<$msg_enum>, DecodeError> {
+				$(
+					if let Some(msg) = self.$field.read(message_type, buffer)? {
+						return Ok(Some($msg_enum::$variant(msg)));
+					}
+				)+
+				Ok(None)
+			}
+		}
+
+		impl CustomMessageHandler for $handler_name {
+			fn handle_custom_message(
+				&self, msg: $msg_enum, sender_node_id: &PublicKey,
+			) -> Result<(), LightningError> {
+				match msg {
+					$( $msg_enum::$variant(msg) => {
+						self.$field.handle_custom_message(msg, sender_node_id)
+					} )+
+				}
+			}
+
+			fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, $msg_enum)> {
+				let mut pending = Vec::new();
+				$(
+					pending.extend(
+						self.$field
+							.get_and_clear_pending_msg()
+							.into_iter()
+							.map(|(pubkey, msg)| (pubkey, $msg_enum::$variant(msg))),
+					);
+				)+
+				pending
+			}
+		}
+	};
+}
+
+/// A trivial second custom-message type that just echoes its payload back to
+/// whoever sent it. It exists only to give [`composite_custom_message_handler`]
+/// a real second handler to compose [`TowerMessageHandler`] with, so the
+/// macro's generated code is actually type-checked and exercised.
+#[derive(Debug)]
+pub struct EchoMessage {
+	pub payload: Vec<u8>,
+}
+
+impl Encode for EchoMessage {
+	// An arbitrary odd type, disjoint from every `TowerMessage` type id.
+	const TYPE: u16 = 45791;
+}
+
+impl Type for EchoMessage {
+	fn type_id(&self) -> u16 {
+		Self::TYPE
+	}
+}
+
+impl Writeable for EchoMessage {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_tlv_field(writer, 0, &self.payload)
+	}
+}
+
+impl Readable for EchoMessage {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut payload = None;
+		for (field_type, value) in read_tlv_stream(reader)? {
+			match field_type {
+				0 => payload = Some(parse_tlv_value(&value)?),
+				t if t % 2 == 0 => return Err(DecodeError::InvalidValue),
+				_ => {}
+			}
+		}
+		Ok(Self { payload: payload.ok_or(DecodeError::InvalidValue)? })
+	}
+}
+
+/// Hands every [`EchoMessage`] it receives straight back to its sender.
+pub struct EchoMessageHandler {
+	msg_q: Mutex<Vec<(PublicKey, EchoMessage)>>,
+}
+
+impl EchoMessageHandler {
+	pub fn new() -> Self {
+		Self { msg_q: Mutex::new(Vec::new()) }
+	}
+}
+
+impl CustomMessageReader for EchoMessageHandler {
+	type CustomMessage = EchoMessage;
+
+	fn read<R: io::Read>(
+		&self, message_type: u16, buffer: &mut R,
+	) -> Result<Option<EchoMessage>, DecodeError> {
+		match message_type {
+			EchoMessage::TYPE => Ok(Some(Readable::read(buffer)?)),
+			_ => Ok(None),
+		}
+	}
+}
+
+impl CustomMessageHandler for EchoMessageHandler {
+	fn handle_custom_message(
+		&self, msg: EchoMessage, sender_node_id: &PublicKey,
+	) -> Result<(), LightningError> {
+		self.msg_q.lock().unwrap().push((sender_node_id.clone(), msg));
+		Ok(())
+	}
+
+	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, EchoMessage)> {
+		mem::replace(&mut self.msg_q.lock().unwrap(), Vec::new())
+	}
+}
+
+composite_custom_message_handler!(CombinedMessageHandler, CombinedMessage, {
+	tower: TowerMessageHandler => TowerMessage as Tower,
+	echo: EchoMessageHandler => EchoMessage as Echo,
+});
+
 /// A type similar to [`SimpleArcPeerManager`] but uses [`TowerMessageHandler`]
-/// instead of [`IgnoringMessageHandler`] for the handling of custom messages.
+/// (or, via [`composite_custom_message_handler`], a combination of it with
+/// other application-specific handlers) instead of [`IgnoringMessageHandler`]
+/// for the handling of custom messages.
 ///
 /// [`SimpleArcPeerManager`]: lightning::ln::peer_handler::SimpleArcPeerManager
 /// [`IgnoringMessageHandler`]: lightning::ln::peer_handler::IgnoringMessageHandler
-pub type SimpleTowerArcPeerManager<SD, M, T, F, C, L> = PeerManager<
+pub type SimpleTowerArcPeerManager<SD, M, T, F, C, L, CMH = TowerMessageHandler> = PeerManager<
 	SD,
 	Arc<SimpleArcChannelManager<M, T, F, L>>,
 	Arc<NetGraphMsgHandler<Arc<NetworkGraph>, Arc<C>, Arc<L>>>,
 	Arc<L>,
-	Arc<TowerMessageHandler>,
+	Arc<CMH>,
 >;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_pubkey() -> PublicKey {
+		test_pubkey_with(0x42)
+	}
+
+	fn test_pubkey_with(byte: u8) -> PublicKey {
+		let secp_ctx = Secp256k1::new();
+		PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[byte; 32]).unwrap())
+	}
+
+	struct RecordingBroadcaster {
+		broadcast: Mutex<Vec<Transaction>>,
+	}
+	impl BroadcasterInterface for RecordingBroadcaster {
+		fn broadcast_transaction(&self, tx: &Transaction) {
+			self.broadcast.lock().unwrap().push(tx.clone());
+		}
+	}
+
+	fn test_tower_handler() -> (TowerMessageHandler, Arc<RecordingBroadcaster>) {
+		let broadcaster = Arc::new(RecordingBroadcaster { broadcast: Mutex::new(Vec::new()) });
+		let handler =
+			TowerMessageHandler::new(SecretKey::from_slice(&[0x01; 32]).unwrap(), broadcaster.clone());
+		(handler, broadcaster)
+	}
+
+	fn register(handler: &TowerMessageHandler, subscriber: &PublicKey, slots: u32, period: u32) {
+		let register = Register { pubkey: subscriber.clone(), appointment_slots: slots, subscription_period: period };
+		handler.handle_tower_message(subscriber, TowerMessage::Register(register)).unwrap();
+	}
+
+	#[test]
+	fn composite_handler_dispatches_each_message_to_its_own_inner_handler() {
+		let (tower_handler, _broadcaster) = test_tower_handler();
+		let combined =
+			CombinedMessageHandler::new(Arc::new(tower_handler), Arc::new(EchoMessageHandler::new()));
+		let sender = test_pubkey();
+
+		// A tower message is recognized and routed to the tower handler...
+		let register = Register { pubkey: sender, appointment_slots: 1, subscription_period: 10 };
+		let mut wire = TlvValueWriter(Vec::new());
+		register.write(&mut wire).unwrap();
+		let read = combined.read(Register::TYPE, &mut &wire.0[..]).unwrap().unwrap();
+		combined.handle_custom_message(read, &sender).unwrap();
+		assert!(matches!(
+			combined.tower.get_and_clear_pending_msg().as_slice(),
+			[(pubkey, TowerMessage::SubscriptionDetails(_))] if *pubkey == sender
+		));
+
+		// ...and an echo message is recognized and routed to the echo handler,
+		// without the tower handler's `read` ever consuming the buffer.
+		let echo = EchoMessage { payload: vec![1, 2, 3] };
+		let mut wire = TlvValueWriter(Vec::new());
+		echo.write(&mut wire).unwrap();
+		let read = combined.read(EchoMessage::TYPE, &mut &wire.0[..]).unwrap().unwrap();
+		combined.handle_custom_message(read, &sender).unwrap();
+		let pending = combined.echo.get_and_clear_pending_msg();
+		assert_eq!(pending.len(), 1);
+		assert_eq!(pending[0].0, sender);
+		assert_eq!(pending[0].1.payload, vec![1, 2, 3]);
+
+		// An unrecognized message type is rejected by every inner handler.
+		assert!(combined.read(u16::MAX, &mut &[][..]).unwrap().is_none());
+	}
+
+	#[test]
+	fn updating_an_existing_appointment_does_not_spend_a_second_slot() {
+		let (handler, _broadcaster) = test_tower_handler();
+		let subscriber = test_pubkey();
+		register(&handler, &subscriber, 1, 1000);
+
+		let add = |blob: Vec<u8>| {
+			handler
+				.handle_tower_message(
+					&subscriber,
+					TowerMessage::AddUpdateAppointment(AddUpdateAppointment {
+						locator: [7; 16],
+						encrypted_blob: blob,
+						to_self_delay: 42,
+					}),
+				)
+				.unwrap()
+		};
+		assert!(matches!(add(vec![1, 2, 3]), TowerMessage::AppointmentAccepted(_)));
+		// Re-sending the same locator is an update, not a second appointment,
+		// so it must succeed even though only one slot was ever available.
+		assert!(matches!(add(vec![4, 5, 6]), TowerMessage::AppointmentAccepted(_)));
+
+		// A second locator would need a second slot, which isn't available.
+		let rejected = handler
+			.handle_tower_message(
+				&subscriber,
+				TowerMessage::AddUpdateAppointment(AddUpdateAppointment {
+					locator: [8; 16],
+					encrypted_blob: vec![],
+					to_self_delay: 42,
+				}),
+			)
+			.unwrap();
+		assert!(matches!(rejected, TowerMessage::AppointmentRejected(_)));
+	}
+
+	#[test]
+	fn a_locator_already_owned_by_another_subscriber_cannot_be_taken_over() {
+		let (handler, _broadcaster) = test_tower_handler();
+		let alice = test_pubkey_with(0x01);
+		let bob = test_pubkey_with(0x02);
+		register(&handler, &alice, 1, 1000);
+		register(&handler, &bob, 1, 1000);
+
+		let locator = [9; 16];
+		let add = |subscriber: &PublicKey, blob: Vec<u8>| {
+			handler
+				.handle_tower_message(
+					subscriber,
+					TowerMessage::AddUpdateAppointment(AddUpdateAppointment {
+						locator,
+						encrypted_blob: blob,
+						to_self_delay: 42,
+					}),
+				)
+				.unwrap()
+		};
+		assert!(matches!(add(&alice, vec![1]), TowerMessage::AppointmentAccepted(_)));
+		// Bob can't steal Alice's locator, and his own slot isn't charged for trying.
+		assert!(matches!(add(&bob, vec![2]), TowerMessage::AppointmentRejected(_)));
+
+		// Alice still owns the appointment and can read it back; Bob can't.
+		let get = |subscriber: &PublicKey| {
+			handler
+				.handle_tower_message(subscriber, TowerMessage::GetAppointment(GetAppointment { locator }))
+				.unwrap()
+		};
+		assert!(matches!(get(&alice), TowerMessage::AppointmentData(_)));
+		assert!(matches!(get(&bob), TowerMessage::AppointmentRejected(_)));
+	}
+
+	#[test]
+	fn resolving_a_breach_broadcasts_the_penalty_tx_and_restores_the_slot() {
+		let (handler, broadcaster) = test_tower_handler();
+		let subscriber = test_pubkey();
+		register(&handler, &subscriber, 1, 1000);
+
+		// The locator is the breach txid's first half, the decryption key its
+		// second half, so derive both from a real transaction's txid.
+		let breach_tx = Transaction { version: 2, lock_time: 0, input: Vec::new(), output: Vec::new() };
+		let txid_bytes = *breach_tx.txid().as_inner();
+		let locator: Locator = txid_bytes[..16].try_into().unwrap();
+		let key: [u8; 16] = txid_bytes[16..].try_into().unwrap();
+
+		let penalty_tx = Transaction { version: 3, lock_time: 0, input: Vec::new(), output: Vec::new() };
+		let plaintext = bitcoin::consensus::encode::serialize(&penalty_tx);
+		let cipher = Aes128Gcm::new(Key::from_slice(&key));
+		let encrypted_blob = cipher.encrypt(Nonce::from_slice(&[0u8; 12]), plaintext.as_ref()).unwrap();
+
+		handler
+			.handle_tower_message(
+				&subscriber,
+				TowerMessage::AddUpdateAppointment(AddUpdateAppointment {
+					locator,
+					encrypted_blob,
+					to_self_delay: 42,
+				}),
+			)
+			.unwrap();
+
+		handler.block_connected(&Block { header: test_block_header(), txdata: vec![breach_tx] }, 1);
+
+		assert_eq!(broadcaster.broadcast.lock().unwrap().len(), 1);
+		// The slot that the appointment spent is given back once it resolves.
+		let accepted = handler
+			.handle_tower_message(
+				&subscriber,
+				TowerMessage::AddUpdateAppointment(AddUpdateAppointment {
+					locator: [1; 16],
+					encrypted_blob: vec![],
+					to_self_delay: 42,
+				}),
+			)
+			.unwrap();
+		assert!(matches!(accepted, TowerMessage::AppointmentAccepted(_)));
+	}
+
+	fn test_block_header() -> BlockHeader {
+		BlockHeader {
+			version: 1,
+			prev_blockhash: Default::default(),
+			merkle_root: Default::default(),
+			time: 0,
+			bits: 0,
+			nonce: 0,
+		}
+	}
+
+	#[test]
+	fn repeated_registration_saturates_instead_of_overflowing() {
+		let (handler, _broadcaster) = test_tower_handler();
+		let subscriber = test_pubkey();
+		register(&handler, &subscriber, u32::MAX, u32::MAX);
+		// A second registration would overflow available_slots, expiry_block and
+		// amount_msat with wrapping/checked arithmetic; it must not panic.
+		let response = handler
+			.handle_tower_message(
+				&subscriber,
+				TowerMessage::Register(Register {
+					pubkey: subscriber,
+					appointment_slots: u32::MAX,
+					subscription_period: u32::MAX,
+				}),
+			)
+			.unwrap();
+		assert!(matches!(response, TowerMessage::SubscriptionDetails(_)));
+	}
+
+	#[test]
+	fn tlv_stream_round_trips_through_write_and_read() {
+		let register = Register { pubkey: test_pubkey(), appointment_slots: 7, subscription_period: 144 };
+		let mut buf = TlvValueWriter(Vec::new());
+		register.write(&mut buf).unwrap();
+		let read_back = Register::read(&mut &buf.0[..]).unwrap();
+		assert_eq!(read_back.pubkey, register.pubkey);
+		assert_eq!(read_back.appointment_slots, register.appointment_slots);
+		assert_eq!(read_back.subscription_period, register.subscription_period);
+	}
+
+	#[test]
+	fn tlv_stream_rejects_out_of_order_types() {
+		// Field types 4 then 2: descending, which BOLT #1 forbids.
+		let mut writer = TlvValueWriter(Vec::new());
+		BigSize(4).write(&mut writer).unwrap();
+		BigSize(4).write(&mut writer).unwrap();
+		writer.0.extend_from_slice(&[0u8; 4]);
+		BigSize(2).write(&mut writer).unwrap();
+		BigSize(4).write(&mut writer).unwrap();
+		writer.0.extend_from_slice(&[0u8; 4]);
+		let wire = writer.0;
+		assert!(matches!(read_tlv_stream(&mut &wire[..]), Err(DecodeError::InvalidValue)));
+	}
+
+	#[test]
+	fn tlv_stream_rejects_unknown_even_type_but_skips_unknown_odd_type() {
+		// Type 1 (odd/optional, unknown) is consumed whole, value bytes
+		// included, and handed back for the message reader to ignore.
+		let mut writer = TlvValueWriter(Vec::new());
+		BigSize(1).write(&mut writer).unwrap();
+		BigSize(3).write(&mut writer).unwrap();
+		writer.0.extend_from_slice(&[0xaa; 3]);
+		let mut wire = writer.0.clone();
+		let records = read_tlv_stream(&mut &wire[..]).unwrap();
+		assert_eq!(records, vec![(1, vec![0xaa; 3])]);
+
+		// `Register::read` skips an unknown odd record (type 5) and still
+		// parses the rest of the stream.
+		let register = Register { pubkey: test_pubkey(), appointment_slots: 7, subscription_period: 144 };
+		let mut writer = TlvValueWriter(Vec::new());
+		register.write(&mut writer).unwrap();
+		BigSize(5).write(&mut writer).unwrap();
+		BigSize(3).write(&mut writer).unwrap();
+		writer.0.extend_from_slice(&[0xaa; 3]);
+		let read_back = Register::read(&mut &writer.0[..]).unwrap();
+		assert_eq!(read_back.appointment_slots, 7);
+		assert_eq!(read_back.subscription_period, 144);
+
+		// Type 6 (even/mandatory, unknown to `Register::read`) must error out.
+		let register_wire = {
+			let mut writer = TlvValueWriter(Vec::new());
+			BigSize(6).write(&mut writer).unwrap();
+			BigSize(3).write(&mut writer).unwrap();
+			writer.0.extend_from_slice(&[0xaa; 3]);
+			writer.0
+		};
+		wire = register_wire;
+		assert!(matches!(Register::read(&mut &wire[..]), Err(DecodeError::InvalidValue)));
+	}
+
+	#[test]
+	fn tlv_stream_caps_declared_record_length_before_allocating() {
+		// A record that claims to be far larger than any real Lightning
+		// message must be rejected before we ever try to allocate for it.
+		let mut writer = TlvValueWriter(Vec::new());
+		BigSize(0).write(&mut writer).unwrap();
+		BigSize(MAX_TLV_RECORD_LEN + 1).write(&mut writer).unwrap();
+		let wire = writer.0;
+		assert!(matches!(read_tlv_stream(&mut &wire[..]), Err(DecodeError::InvalidValue)));
+	}
+}